@@ -6,7 +6,7 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::Read;
 
-use libc::{c_char, c_uint};
+use libc::{c_char, c_uint, c_void};
 
 use rustation::{Disc, Region};
 use rustation::bios::{Bios, BIOS_SIZE};
@@ -27,7 +27,9 @@ macro_rules! cstring {
 const SYSTEM_INFO: libretro::SystemInfo = libretro::SystemInfo {
     library_name: cstring!("Rustation"),
     library_version: rustation::VERSION_CSTR as *const _ as *const c_char,
-    valid_extensions: cstring!("bin"),
+    // Single disc images (`bin`/`cue`) or an `m3u` playlist listing
+    // several of them for multi-disc games.
+    valid_extensions: cstring!("bin|cue|m3u"),
     need_fullpath: false,
     block_extract: false,
 };
@@ -35,24 +37,172 @@ const SYSTEM_INFO: libretro::SystemInfo = libretro::SystemInfo {
 /// Emulator context
 struct Context {
     retrogl: retrogl::RetroGl,
+    /// The discs making up the loaded game, in playlist order. A
+    /// single-disc game holds exactly one entry.
+    discs: Vec<Disc>,
+    /// Index of the disc currently mounted in the optical drive
+    disc_index: usize,
+    /// `true` while the frontend holds the virtual drive tray open. A
+    /// disc may only be swapped while the tray is ejected.
+    eject_state: bool,
+    /// Interleaved stereo `i16` audio, refilled every frame and
+    /// submitted to the frontend through the batch audio callback. The
+    /// SPU is not read, so it holds silence.
+    audio_buffer: Vec<i16>,
+    /// Controller state for each port, refreshed every frame from the
+    /// frontend's input callbacks.
+    pads: [PadState; PORTS],
+    /// Internal upscaling factor currently applied, cached so we only
+    /// rebuild the GL framebuffer when the core option actually changes.
+    upscale: u32,
+    /// Region the AV timing and geometry are currently derived from.
+    /// Either the detected disc region or the region override.
+    region: Region,
+    /// Both memory-card images laid out back to back (slot 1 then slot
+    /// 2). Boxed so the address stays fixed for the lifetime of the
+    /// context and the frontend can read it between frames to persist
+    /// the save file.
+    memory_cards: Box<[u8; MEMORY_CARD_SIZE * 2]>,
 }
 
-impl Context {
-    fn new(disc: &Path) -> Result<Context, ()> {
+/// Video standard the emulated console runs at, picked from the active
+/// `Region`. PAL consoles refresh slower and show a taller active area.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum VideoStandard {
+    Ntsc,
+    Pal,
+}
 
-        let disc =
-            match Disc::from_path(&disc) {
-                Ok(d) => d,
-                Err(e) => {
-                    error!("Couldn't load {}: {}", disc.to_string_lossy(), e);
-                    return Err(());
-                }
-            };
+impl VideoStandard {
+    fn from_region(region: Region) -> VideoStandard {
+        match region {
+            Region::Europe => VideoStandard::Pal,
+            _ => VideoStandard::Ntsc,
+        }
+    }
+
+    /// Nominal refresh rate in Hz
+    fn fps(self) -> f32 {
+        match self {
+            VideoStandard::Ntsc => 59.94,
+            VideoStandard::Pal => 50.0,
+        }
+    }
 
-        let region = disc.region();
+    /// Height of the active display area in native pixels. The width is
+    /// unchanged between standards (640 at full horizontal resolution);
+    /// PAL just packs more scanlines.
+    fn active_height(self) -> u32 {
+        match self {
+            VideoStandard::Ntsc => 480,
+            VideoStandard::Pal => 576,
+        }
+    }
+
+    /// Number of 44.1 kHz stereo sample pairs per frame at this
+    /// standard's refresh rate, rounded down (735 for NTSC, 882 for PAL).
+    /// The fraction dropped at 59.94 Hz is not carried over to later
+    /// frames.
+    fn samples_per_frame(self) -> usize {
+        (44_100.0 / self.fps()) as usize
+    }
+}
+
+/// Number of controller ports exposed by the PlayStation
+const PORTS: usize = 2;
+
+/// Size in bytes of a single PlayStation memory card image (128 KB)
+const MEMORY_CARD_SIZE: usize = 128 * 1024;
+
+/// Kind of peripheral plugged into a controller port. Selected by the
+/// frontend through `set_controller_port_device`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PadProfile {
+    /// No controller plugged in
+    Disconnected,
+    /// Original digital pad (SCPH-1080)
+    Digital,
+    /// DualShock with two analog sticks
+    DualShock,
+}
+
+/// Digital button and analog stick state for a single port, rebuilt
+/// each frame from the frontend's input state.
+#[derive(Clone, Copy)]
+struct PadState {
+    /// Which peripheral the frontend has assigned to this port
+    profile: PadProfile,
+    /// Pressed buttons as a PlayStation digital-pad bitmask
+    buttons: u16,
+    /// Left and right analog sticks as `(x, y)` pairs centred on 0x80,
+    /// only meaningful for `PadProfile::DualShock`
+    left_stick: (u8, u8),
+    right_stick: (u8, u8),
+}
+
+impl PadState {
+    fn new() -> PadState {
+        PadState {
+            profile: PadProfile::Disconnected,
+            buttons: 0,
+            left_stick: (0x80, 0x80),
+            right_stick: (0x80, 0x80),
+        }
+    }
+}
+
+/// Map from libretro joypad button ids to the PlayStation digital-pad
+/// bit layout. The L3/R3 entries are only meaningful on a DualShock but
+/// are harmless to poll for a digital pad since the frontend reports
+/// them as unpressed.
+const JOYPAD_MAP: [(u32, u16); 16] = [
+    (libretro::joypad::SELECT, 1 << 0),
+    (libretro::joypad::L3,     1 << 1),
+    (libretro::joypad::R3,     1 << 2),
+    (libretro::joypad::START,  1 << 3),
+    (libretro::joypad::UP,     1 << 4),
+    (libretro::joypad::RIGHT,  1 << 5),
+    (libretro::joypad::DOWN,   1 << 6),
+    (libretro::joypad::LEFT,   1 << 7),
+    (libretro::joypad::L2,     1 << 8),
+    (libretro::joypad::R2,     1 << 9),
+    (libretro::joypad::L,      1 << 10),
+    (libretro::joypad::R,      1 << 11),
+    (libretro::joypad::X,      1 << 12), // Triangle
+    (libretro::joypad::A,      1 << 13), // Circle
+    (libretro::joypad::B,      1 << 14), // Cross
+    (libretro::joypad::Y,      1 << 15), // Square
+];
+
+impl Context {
+    fn new(discs: &[PathBuf]) -> Result<Context, ()> {
+        let discs = try!(Context::load_discs(discs));
+
+        // `load_discs` guarantees at least one disc
+        let region = discs[0].region();
 
         info!("Detected disc region: {:?}", region);
 
+        // A region forced through the core options wins over the disc's
+        Context::from_parts(discs, region_override().unwrap_or(region))
+    }
+
+    /// Boot with no disc in the drive, the way real hardware powers up
+    /// into the SCPH menu/CD player. Since there is no disc to sniff a
+    /// region from, it comes from the region core option (NTSC-U when
+    /// that option is `Auto`).
+    fn new_no_disc() -> Result<Context, ()> {
+        let region = forced_region();
+
+        info!("Booting with no disc, region {:?}", region);
+
+        Context::from_parts(Vec::new(), region)
+    }
+
+    /// Shared constructor: locate a BIOS for `region`, bring up the GL
+    /// state and assemble the context around `discs` (which may be
+    /// empty for a no-disc boot).
+    fn from_parts(discs: Vec<Disc>, region: Region) -> Result<Context, ()> {
         let _bios =
             match find_bios(region) {
                 Some(b) => b,
@@ -66,13 +216,261 @@ impl Context {
 
         Ok(Context {
             retrogl: retrogl,
+            discs: discs,
+            disc_index: 0,
+            eject_state: false,
+            audio_buffer:
+                Vec::with_capacity(
+                    VideoStandard::from_region(region).samples_per_frame() * 2),
+            // Port 1 defaults to a digital pad, port 2 starts empty
+            pads: [
+                PadState { profile: PadProfile::Digital, ..PadState::new() },
+                PadState::new(),
+            ],
+            upscale: internal_upscale(),
+            region: region,
+            memory_cards: Box::new([0; MEMORY_CARD_SIZE * 2]),
         })
     }
+
+    /// Load every disc image in `paths` into a `Disc`. An empty slice
+    /// is an error since a game needs at least one disc.
+    fn load_discs(paths: &[PathBuf]) -> Result<Vec<Disc>, ()> {
+        if paths.is_empty() {
+            error!("No disc image to load");
+            return Err(());
+        }
+
+        let mut discs = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            match Disc::from_path(path) {
+                Ok(d) => discs.push(d),
+                Err(e) => {
+                    error!("Couldn't load {}: {}", path.to_string_lossy(), e);
+                    return Err(());
+                }
+            }
+        }
+
+        Ok(discs)
+    }
+
+    /// Return the disc currently mounted in the optical drive, or
+    /// `None` when the drive is empty (no-disc boot).
+    fn active_disc(&self) -> Option<&Disc> {
+        self.discs.get(self.disc_index)
+    }
+
+    /// Hand one video frame's worth of audio to the frontend through the
+    /// batch audio callback. The samples are interleaved stereo `i16`
+    /// (left then right), which is what `submit_audio_frames` expects.
+    /// The SPU is not read: every sample submitted is silence.
+    fn render_audio(&mut self) {
+        // Scratch buffer reused across frames to avoid reallocating.
+        self.audio_buffer.clear();
+
+        // One PSX audio frame is a left/right pair; one video frame's
+        // worth is `samples_per_frame()` pairs, rounded down, so at
+        // 59.94 Hz slightly fewer than 44 100 pairs go out per second.
+        let frames = VideoStandard::from_region(self.region).samples_per_frame();
+        self.audio_buffer.resize(frames * 2, 0);
+
+        // The buffer stays zero-filled: the context holds no SPU, so
+        // each frame submits silence of that length.
+
+        libretro::submit_audio_frames(&self.audio_buffer);
+    }
+
+    /// Re-read the core options after the frontend signalled a change.
+    /// A new upscaling factor rebuilds the GL framebuffer, and a new
+    /// region override re-derives the AV timing; either change prompts
+    /// the frontend to re-read the AV info so the window and pacing
+    /// follow along.
+    fn refresh_variables(&mut self) {
+        let upscale = internal_upscale();
+        let region =
+            region_override().unwrap_or_else(|| self.detected_region());
+
+        let changed = upscale != self.upscale || region != self.region;
+
+        if upscale != self.upscale {
+            info!("Internal resolution changed to {}x", upscale);
+            self.upscale = upscale;
+            self.retrogl.set_upscale(upscale);
+        }
+
+        if region != self.region {
+            info!("Region changed to {:?}", region);
+            self.region = region;
+        }
+
+        if changed {
+            libretro::refresh_av_info(&self.get_system_av_info());
+        }
+    }
+
+    /// Region implied by the mounted disc, falling back to the forced
+    /// region when the drive is empty.
+    fn detected_region(&self) -> Region {
+        match self.active_disc() {
+            Some(disc) => disc.region(),
+            None => forced_region(),
+        }
+    }
+
+    /// Re-derive the active region after a disc swap and, when it
+    /// differs, refresh the AV info so PAL/NTSC pacing follows the new
+    /// disc.
+    fn update_region(&mut self) {
+        let region =
+            region_override().unwrap_or_else(|| self.detected_region());
+
+        if region != self.region {
+            info!("Region changed to {:?} after disc swap", region);
+            self.region = region;
+            libretro::refresh_av_info(&self.get_system_av_info());
+        }
+    }
+
+    /// Poll the frontend once and refresh every connected port's button
+    /// and analog state in `pads`. Called at the top of each frame,
+    /// before rendering. The state is only stored in `pads`; nothing in
+    /// the context reads it back.
+    fn poll_controllers(&mut self) {
+        libretro::input_poll();
+
+        for port in 0..PORTS {
+            let pad = &mut self.pads[port];
+
+            if pad.profile == PadProfile::Disconnected {
+                continue;
+            }
+
+            let mut buttons = 0;
+
+            for &(id, mask) in JOYPAD_MAP.iter() {
+                if libretro::joypad_pressed(port, id) {
+                    buttons |= mask;
+                }
+            }
+
+            pad.buttons = buttons;
+
+            if pad.profile == PadProfile::DualShock {
+                pad.left_stick =
+                    read_stick(port, libretro::analog::LEFT);
+                pad.right_stick =
+                    read_stick(port, libretro::analog::RIGHT);
+            }
+        }
+    }
+}
+
+/// Read an analog stick for `port` and fold the frontend's signed
+/// `[-0x8000, 0x7fff]` range down to the PlayStation's unsigned 8-bit
+/// axes centred on 0x80.
+fn read_stick(port: usize, stick: u32) -> (u8, u8) {
+    let axis = |id| {
+        let v = libretro::analog_state(port, stick, id) as i32;
+        (((v + 0x8000) >> 8) & 0xff) as u8
+    };
+
+    (axis(libretro::analog::X), axis(libretro::analog::Y))
+}
+
+/// Implementation of the libretro disk-control interface used by the
+/// frontend to swap discs for multi-disc games without reloading the
+/// core.
+impl libretro::DiskControl for Context {
+    fn get_num_images(&self) -> usize {
+        self.discs.len()
+    }
+
+    fn get_image_index(&self) -> usize {
+        self.disc_index
+    }
+
+    fn set_image_index(&mut self, index: usize) -> bool {
+        if !self.eject_state {
+            // The frontend should only change the index while the tray
+            // is open, refuse otherwise.
+            warn!("Refusing disc swap while the tray is closed");
+            return false;
+        }
+
+        if index >= self.discs.len() {
+            warn!("Out of range disc index {}", index);
+            return false;
+        }
+
+        self.disc_index = index;
+        true
+    }
+
+    fn get_eject_state(&self) -> bool {
+        self.eject_state
+    }
+
+    fn set_eject_state(&mut self, ejected: bool) -> bool {
+        if self.eject_state == ejected {
+            return true;
+        }
+
+        self.eject_state = ejected;
+
+        if !ejected {
+            // The tray is being closed: the disc at `disc_index` is now
+            // the one `active_disc` returns. The context holds no
+            // emulator, so nothing else is told about the new disc.
+            match self.active_disc() {
+                Some(disc) =>
+                    info!("Mounted disc {} ({:?})",
+                          self.disc_index, disc.region()),
+                None => info!("Closed the tray with no disc inserted"),
+            }
+
+            // A swapped disc may belong to a different region, re-derive
+            // the timing so PAL/NTSC pacing stays accurate.
+            self.update_region();
+        }
+
+        true
+    }
+
+    fn replace_image_index(&mut self, index: usize, disc: &Path) -> bool {
+        if !self.eject_state {
+            warn!("Refusing disc replacement while the tray is closed");
+            return false;
+        }
+
+        if index >= self.discs.len() {
+            warn!("Out of range disc index {}", index);
+            return false;
+        }
+
+        match Disc::from_path(disc) {
+            Ok(d) => {
+                self.discs[index] = d;
+                true
+            }
+            Err(e) => {
+                error!("Couldn't load {}: {}", disc.to_string_lossy(), e);
+                false
+            }
+        }
+    }
 }
 
 impl libretro::Context for Context {
 
     fn render_frame(&mut self) {
+        if libretro::variables_changed() {
+            self.refresh_variables();
+        }
+
+        self.poll_controllers();
+
         match self.retrogl.state() {
             Some(s) => {
                 if let Err(e) = s.render_frame() {
@@ -85,25 +483,75 @@ impl libretro::Context for Context {
             }
         }
 
-        libretro::gl_frame_done(self.retrogl.xres(), self.retrogl.yres())
+        libretro::gl_frame_done(self.retrogl.xres(), self.retrogl.yres());
+
+        self.render_audio();
     }
 
     fn get_system_av_info(&self) -> libretro::SystemAvInfo {
+        let upscale = internal_upscale();
+        let standard = VideoStandard::from_region(self.region);
+
         libretro::SystemAvInfo {
             geometry: libretro::GameGeometry {
-                base_width: self.retrogl.xres() as c_uint,
-                base_height: self.retrogl.yres() as c_uint,
-                max_width: 640,
-                max_height: 576,
+                base_width: self.retrogl.xres() as c_uint * upscale,
+                base_height: self.retrogl.yres() as c_uint * upscale,
+                max_width: 640 * upscale,
+                max_height: standard.active_height() as c_uint * upscale,
                 aspect_ratio: -1.0,
             },
             timing: libretro::SystemTiming {
-                fps: 60.,
+                fps: standard.fps() as f64,
+                // The sample rate is fixed by the SPU hardware; only the
+                // number of samples handed over per frame follows the
+                // refresh rate (see `render_audio`).
                 sample_rate: 44_100.
             }
         }
     }
 
+    fn set_controller_port_device(&mut self, port: usize, device: c_uint) {
+        if port >= PORTS {
+            warn!("Ignoring device change on unknown port {}", port);
+            return;
+        }
+
+        let profile =
+            match device {
+                libretro::device::NONE => PadProfile::Disconnected,
+                libretro::device::JOYPAD => PadProfile::Digital,
+                libretro::device::ANALOG => PadProfile::DualShock,
+                _ => {
+                    warn!("Unsupported device {:#x} on port {}, \
+                           defaulting to digital pad", device, port);
+                    PadProfile::Digital
+                }
+            };
+
+        self.pads[port] = PadState { profile: profile, ..PadState::new() };
+    }
+
+    fn get_memory_data(&mut self, id: c_uint) -> *mut c_void {
+        if id == libretro::memory::SAVE_RAM {
+            self.memory_cards.as_mut_ptr() as *mut c_void
+        } else {
+            ::std::ptr::null_mut()
+        }
+    }
+
+    fn get_memory_size(&self, id: c_uint) -> usize {
+        if id == libretro::memory::SAVE_RAM {
+            // One card, or both back to back when the second slot is on.
+            if memcard2_enabled() {
+                MEMORY_CARD_SIZE * 2
+            } else {
+                MEMORY_CARD_SIZE
+            }
+        } else {
+            0
+        }
+    }
+
     fn gl_context_reset(&mut self) {
         self.retrogl.context_reset();
     }
@@ -113,17 +561,170 @@ impl libretro::Context for Context {
     }
 }
 
+/// Core options exposed to the frontend. The upscaling factor and the
+/// region are re-read whenever the frontend signals a change, and the
+/// second card slot whenever the save-RAM size is queried, so toggling
+/// them in the menu takes effect live. The BIOS file is only read when
+/// the game is loaded.
+const VARIABLES: [libretro::Variable; 4] = [
+    libretro::Variable {
+        key: cstring!("rustation_internal_resolution"),
+        value: cstring!(
+            "Internal upscaling factor; \
+             1x|2x|4x|8x"),
+    },
+    libretro::Variable {
+        key: cstring!("rustation_region"),
+        value: cstring!(
+            "Console region; \
+             Auto|NTSC-U|NTSC-J|PAL"),
+    },
+    libretro::Variable {
+        key: cstring!("rustation_bios"),
+        value: cstring!(
+            "BIOS file (in the system directory); \
+             scan"),
+    },
+    libretro::Variable {
+        key: cstring!("rustation_memcard2"),
+        value: cstring!(
+            "Enable second memory card slot; \
+             enabled|disabled"),
+    },
+];
+
+/// Whether the second memory-card slot is exposed to the frontend. When
+/// disabled only the first 128 KB card is persisted.
+fn memcard2_enabled() -> bool {
+    match libretro::get_variable("rustation_memcard2") {
+        Some(ref v) => v != "disabled",
+        None => true,
+    }
+}
+
 /// Init function, called only once when our core gets loaded
 fn init() {
     retrolog::init();
+
+    // Let the frontend start us with no content so users can reach the
+    // SCPH menu, browse memory cards or play an audio CD.
+    libretro::enable_no_game_support();
+
+    libretro::register_variables(&VARIABLES);
+}
+
+/// Internal upscaling factor selected through the core options. The GL
+/// framebuffer resolution and the reported geometry are multiplied by
+/// this. Falls back to native resolution on a malformed value.
+fn internal_upscale() -> u32 {
+    match libretro::get_variable("rustation_internal_resolution") {
+        Some(ref v) => {
+            // Values look like "2x", keep the leading digits.
+            v.trim_right_matches('x').parse().unwrap_or(1)
+        }
+        None => 1,
+    }
+}
+
+/// Region forced through the core options, or `None` for `Auto` (detect
+/// from the disc).
+fn region_override() -> Option<Region> {
+    match libretro::get_variable("rustation_region") {
+        Some(ref v) => match &v[..] {
+            "NTSC-U" => Some(Region::NorthAmerica),
+            "NTSC-J" => Some(Region::Japan),
+            "PAL"    => Some(Region::Europe),
+            _        => None,
+        },
+        None => None,
+    }
+}
+
+/// Specific BIOS file name the user pinned in the system directory, or
+/// `None` to scan for any matching dump.
+fn bios_override() -> Option<String> {
+    match libretro::get_variable("rustation_bios") {
+        Some(ref v) if v != "scan" => Some(v.clone()),
+        _ => None,
+    }
+}
+
+/// Region to use when there is no disc to detect one from: the region
+/// override if the user forced one, NTSC-U otherwise.
+fn forced_region() -> Region {
+    region_override().unwrap_or(Region::NorthAmerica)
 }
 
-/// Called when a game is loaded and a new context must be built
-fn load_game(disc: PathBuf) -> Option<Box<libretro::Context>> {
-    info!("Loading {:?}", disc);
+/// Called when a game is loaded and a new context must be built. A
+/// `None` (or empty) path means the frontend launched the core with no
+/// content, in which case we boot straight into the BIOS.
+fn load_game(disc: Option<PathBuf>) -> Option<Box<libretro::Context>> {
+    let context =
+        match disc {
+            Some(ref path) if !path.as_os_str().is_empty() => {
+                info!("Loading {:?}", path);
+
+                let discs =
+                    match build_playlist(path) {
+                        Ok(d) => d,
+                        Err(_) => return None,
+                    };
+
+                Context::new(&discs)
+            }
+            _ => {
+                info!("Loading with no disc inserted");
+                Context::new_no_disc()
+            }
+        };
 
-    Context::new(&disc).ok()
-        .map(|c| Box::new(c) as Box<libretro::Context>)
+    context.ok()
+        .map(|c| {
+            // Advertise the disk-control interface so the frontend can
+            // drive disc swaps for multi-disc games.
+            libretro::register_disk_control_interface();
+            Box::new(c) as Box<libretro::Context>
+        })
+}
+
+/// Expand `path` into the list of disc images to load. A `.m3u`
+/// playlist is read line by line (blank lines and `#` comments are
+/// ignored) with relative entries resolved against the playlist's
+/// directory; any other path is loaded as a single-disc game.
+fn build_playlist(path: &Path) -> Result<Vec<PathBuf>, ()> {
+    let is_m3u =
+        path.extension()
+            .map_or(false, |e| e.eq_ignore_ascii_case("m3u"));
+
+    if !is_m3u {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut contents = String::new();
+
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => (),
+        Err(e) => {
+            error!("Can't read playlist {:?}: {}", path, e);
+            return Err(());
+        }
+    }
+
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let discs: Vec<PathBuf> =
+        contents.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| base.join(l))
+            .collect();
+
+    if discs.is_empty() {
+        error!("Playlist {:?} doesn't reference any disc", path);
+        return Err(());
+    }
+
+    Ok(discs)
 }
 
 /// Attempt to find a BIOS for `region` in the system directory
@@ -143,6 +744,16 @@ fn find_bios(region: Region) -> Option<Bios> {
             }
         };
 
+    // If the user pinned a specific BIOS file, use it directly instead
+    // of scanning the whole directory.
+    if let Some(name) = bios_override() {
+        let path = system_directory.join(&name);
+
+        info!("Using BIOS override {:?}", path);
+
+        return try_bios(region, &path);
+    }
+
     info!("Looking for a BIOS for region {:?} in {:?}",
           region,
           system_directory);